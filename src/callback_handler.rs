@@ -0,0 +1,140 @@
+use anyhow::{anyhow, Result};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::cluster_context::ClusterContext;
+use crate::policy::Policy;
+
+/// A request sent by a `PolicyEvaluator`'s host callback to the
+/// `CallbackHandler` task that owns the cluster access and the policy log
+/// sink. Each variant carries the `oneshot` channel the handler uses to
+/// hand the answer back to the waiting waPC host call.
+#[derive(Debug)]
+pub enum CallbackRequest {
+    LogEvent {
+        policy: Policy,
+        message: Vec<u8>,
+        response_channel: oneshot::Sender<Result<()>>,
+    },
+    ListResources {
+        api_version: String,
+        kind: String,
+        namespace: Option<String>,
+        label_selector: Option<String>,
+        field_selector: Option<String>,
+        response_channel: oneshot::Sender<Result<Vec<u8>>>,
+    },
+}
+
+pub type CallbackSender = mpsc::Sender<CallbackRequest>;
+
+/// Owns the single `ClusterContext` used to answer `ListResources` queries
+/// and drains the `CallbackRequest`s sent by every `PolicyEvaluator` in the
+/// process. Running as its own task means the cluster access is no longer
+/// reached through a global singleton from inside the waPC host callback.
+pub struct CallbackHandler {
+    receiver: mpsc::Receiver<CallbackRequest>,
+    cluster_context: ClusterContext,
+}
+
+impl CallbackHandler {
+    pub fn new(receiver: mpsc::Receiver<CallbackRequest>, cluster_context: ClusterContext) -> Self {
+        CallbackHandler {
+            receiver,
+            cluster_context,
+        }
+    }
+
+    pub async fn run(mut self) {
+        while let Some(request) = self.receiver.recv().await {
+            match request {
+                CallbackRequest::LogEvent {
+                    policy,
+                    message,
+                    response_channel,
+                } => {
+                    let _ = response_channel.send(policy.log(&message));
+                }
+                CallbackRequest::ListResources {
+                    kind,
+                    response_channel,
+                    ..
+                } => {
+                    // Only the fixed set of resources the guest SDK already
+                    // exposes are served today; the request shape is wider
+                    // on purpose so richer, context-aware queries can be
+                    // added here without another transport change.
+                    let result = match kind.as_str() {
+                        "Ingress" => Ok(self.cluster_context.ingresses().into()),
+                        "Namespace" => Ok(self.cluster_context.namespaces().into()),
+                        "Service" => Ok(self.cluster_context.services().into()),
+                        other => Err(anyhow!("unsupported resource kind: {}", other)),
+                    };
+                    let _ = response_channel.send(result);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::Policy;
+
+    #[tokio::test]
+    async fn log_event_is_forwarded_to_the_policy_and_acknowledged() {
+        let (tx, rx) = mpsc::channel(1);
+        tokio::spawn(CallbackHandler::new(rx, ClusterContext::default()).run());
+
+        let (response_tx, response_rx) = oneshot::channel();
+        tx.send(CallbackRequest::LogEvent {
+            policy: Policy::default(),
+            message: br#"{"level":"info","message":"hello"}"#.to_vec(),
+            response_channel: response_tx,
+        })
+        .await
+        .unwrap();
+
+        assert!(response_rx.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn list_resources_dispatches_known_kinds() {
+        let (tx, rx) = mpsc::channel(1);
+        tokio::spawn(CallbackHandler::new(rx, ClusterContext::default()).run());
+
+        let (response_tx, response_rx) = oneshot::channel();
+        tx.send(CallbackRequest::ListResources {
+            api_version: "v1".to_string(),
+            kind: "Ingress".to_string(),
+            namespace: None,
+            label_selector: None,
+            field_selector: None,
+            response_channel: response_tx,
+        })
+        .await
+        .unwrap();
+
+        assert!(response_rx.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn list_resources_rejects_unsupported_kinds() {
+        let (tx, rx) = mpsc::channel(1);
+        tokio::spawn(CallbackHandler::new(rx, ClusterContext::default()).run());
+
+        let (response_tx, response_rx) = oneshot::channel();
+        tx.send(CallbackRequest::ListResources {
+            api_version: "v1".to_string(),
+            kind: "Pod".to_string(),
+            namespace: None,
+            label_selector: None,
+            field_selector: None,
+            response_channel: response_tx,
+        })
+        .await
+        .unwrap();
+
+        assert!(response_rx.await.unwrap().is_err());
+    }
+}
@@ -0,0 +1,199 @@
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::Value;
+
+/// A single RFC 6902 JSON Patch operation. Only the three ops a guest's
+/// `mutated_object` can produce are needed here: replacing a changed leaf,
+/// adding a key/index that only exists in the mutated object, and removing
+/// one that only exists in the original.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum JsonPatchOperation {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+}
+
+/// Computes the RFC 6902 JSON Patch that turns `original` into `mutated` by
+/// walking both trees in parallel. Object keys and array indices present
+/// only in `mutated` become `add`s, ones present only in `original` become
+/// `remove`s, and changed leaves become `replace`s.
+pub fn diff(original: &Value, mutated: &Value) -> Vec<JsonPatchOperation> {
+    let mut operations = Vec::new();
+    walk("", original, mutated, &mut operations);
+    operations
+}
+
+/// Base64-encodes the patch array, as expected in the `patch` field of a
+/// Kubernetes `AdmissionResponse`.
+pub fn to_base64(operations: &[JsonPatchOperation]) -> Result<String> {
+    let serialized = serde_json::to_vec(operations)?;
+    Ok(base64::encode(serialized))
+}
+
+fn walk(path: &str, original: &Value, mutated: &Value, operations: &mut Vec<JsonPatchOperation>) {
+    match (original, mutated) {
+        (Value::Object(original_map), Value::Object(mutated_map)) => {
+            for (key, original_value) in original_map {
+                let child_path = append(path, &escape(key));
+                match mutated_map.get(key) {
+                    Some(mutated_value) => {
+                        walk(&child_path, original_value, mutated_value, operations)
+                    }
+                    None => operations.push(JsonPatchOperation::Remove { path: child_path }),
+                }
+            }
+            for (key, mutated_value) in mutated_map {
+                if !original_map.contains_key(key) {
+                    operations.push(JsonPatchOperation::Add {
+                        path: append(path, &escape(key)),
+                        value: mutated_value.clone(),
+                    });
+                }
+            }
+        }
+        (Value::Array(original_items), Value::Array(mutated_items)) => {
+            let common_len = original_items.len().min(mutated_items.len());
+            for (index, (original_item, mutated_item)) in original_items
+                .iter()
+                .zip(mutated_items.iter())
+                .take(common_len)
+                .enumerate()
+            {
+                walk(
+                    &append(path, &index.to_string()),
+                    original_item,
+                    mutated_item,
+                    operations,
+                );
+            }
+            if mutated_items.len() > common_len {
+                for (index, mutated_item) in mutated_items.iter().enumerate().skip(common_len) {
+                    operations.push(JsonPatchOperation::Add {
+                        path: append(path, &index.to_string()),
+                        value: mutated_item.clone(),
+                    });
+                }
+            } else {
+                // Remove from the tail backwards so earlier indices are
+                // still valid by the time each `remove` op is applied.
+                for index in (common_len..original_items.len()).rev() {
+                    operations.push(JsonPatchOperation::Remove {
+                        path: append(path, &index.to_string()),
+                    });
+                }
+            }
+        }
+        _ if original == mutated => {}
+        _ => operations.push(JsonPatchOperation::Replace {
+            path: path.to_string(),
+            value: mutated.clone(),
+        }),
+    }
+}
+
+fn append(path: &str, segment: &str) -> String {
+    format!("{}/{}", path, segment)
+}
+
+/// Escapes a JSON Pointer reference token: `~` as `~0` and `/` as `~1`,
+/// and in that order, since escaping `/` first would reintroduce a `~`
+/// that looks like part of the `~1` escape sequence.
+fn escape(segment: &str) -> String {
+    segment.replace('~', "~0").replace('/', "~1")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn replaces_changed_leaf_values() {
+        let original = json!({"spec": {"replicas": 1}});
+        let mutated = json!({"spec": {"replicas": 3}});
+
+        assert_eq!(
+            diff(&original, &mutated),
+            vec![JsonPatchOperation::Replace {
+                path: "/spec/replicas".to_string(),
+                value: json!(3),
+            }]
+        );
+    }
+
+    #[test]
+    fn adds_and_removes_object_keys() {
+        let original = json!({"labels": {"team": "a"}});
+        let mutated = json!({"labels": {"owner": "b"}});
+
+        let mut ops = diff(&original, &mutated);
+        ops.sort_by_key(|op| match op {
+            JsonPatchOperation::Add { path, .. } => path.clone(),
+            JsonPatchOperation::Remove { path } => path.clone(),
+            JsonPatchOperation::Replace { path, .. } => path.clone(),
+        });
+
+        assert_eq!(
+            ops,
+            vec![
+                JsonPatchOperation::Add {
+                    path: "/labels/owner".to_string(),
+                    value: json!("b"),
+                },
+                JsonPatchOperation::Remove {
+                    path: "/labels/team".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn escapes_tilde_and_slash_in_pointer_paths() {
+        let mut map = serde_json::Map::new();
+        map.insert("a/b~c".to_string(), json!(1));
+        let original = Value::Object(map.clone());
+        map.insert("a/b~c".to_string(), json!(2));
+        let mutated = Value::Object(map);
+
+        assert_eq!(
+            diff(&original, &mutated),
+            vec![JsonPatchOperation::Replace {
+                path: "/a~1b~0c".to_string(),
+                value: json!(2),
+            }]
+        );
+    }
+
+    #[test]
+    fn handles_array_growth_and_shrinkage_by_index() {
+        let original = json!({"items": [1, 2, 3]});
+        let grown = json!({"items": [1, 2, 3, 4]});
+        assert_eq!(
+            diff(&original, &grown),
+            vec![JsonPatchOperation::Add {
+                path: "/items/3".to_string(),
+                value: json!(4),
+            }]
+        );
+
+        let shrunk = json!({"items": [1]});
+        assert_eq!(
+            diff(&original, &shrunk),
+            vec![
+                JsonPatchOperation::Remove {
+                    path: "/items/2".to_string(),
+                },
+                JsonPatchOperation::Remove {
+                    path: "/items/1".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn unchanged_objects_produce_no_operations() {
+        let value = json!({"a": [1, {"b": 2}]});
+        assert!(diff(&value, &value).is_empty());
+    }
+}
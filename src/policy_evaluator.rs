@@ -2,23 +2,81 @@ use anyhow::{anyhow, Result};
 use lazy_static::lazy_static;
 use serde::Serialize;
 use serde_json::{json, value};
-use std::{collections::HashMap, convert::TryFrom, fmt, fs, path::Path, sync::RwLock};
+use std::{
+    convert::TryFrom,
+    fmt, fs,
+    path::Path,
+    sync::{Arc, Mutex, Once, RwLock, Weak},
+    thread,
+    time::Duration,
+};
+use tokio::sync::oneshot;
 use tracing::{error, span, Level};
 
 use wapc::WapcHost;
+use wasmtime::{Config, Engine, Module};
 use wasmtime_provider::WasmtimeEngineProvider;
 
 use kubewarden_policy_sdk::metadata::ProtocolVersion;
 use kubewarden_policy_sdk::response::ValidationResponse as PolicyValidationResponse;
 use kubewarden_policy_sdk::settings::SettingsValidationResponse;
 
-use crate::cluster_context::ClusterContext;
+use crate::callback_handler::{CallbackRequest, CallbackSender};
 use crate::policy::Policy;
+use crate::precompiled_policy::PrecompiledPolicy;
 use crate::validation_response::ValidationResponse;
+use crate::verification_config::{self, PolicySignature, VerificationConfig};
 
+// Wasmtime only lets us bump the "epoch" a `Store` is compared against to
+// decide whether it should trap. A single thread, shared by every evaluator
+// that opted into a `policy_evaluation_limit_seconds`, ticks the epoch of
+// all the engines that requested interruption once a second. This keeps the
+// cost of the timeout mechanism to one thread for the whole process,
+// regardless of how many policies are loaded.
+//
+// Entries are `Weak`, and deduplicated by pointer identity on registration,
+// so building many evaluators from the same `Arc<Engine>` (e.g. repeatedly
+// instantiating one `PrecompiledPolicy`) doesn't register that engine more
+// than once — otherwise the ticker would call `increment_epoch()` on it
+// once per registration per second, silently shrinking every evaluator's
+// `policy_evaluation_limit_seconds` that shares it. Dead engines are
+// dropped from the list as the ticker finds their `Weak` no longer upgrades.
 lazy_static! {
-    static ref POLICY_MAPPING: RwLock<HashMap<u64, Policy>> =
-        RwLock::new(HashMap::with_capacity(64));
+    static ref EPOCH_TICKED_ENGINES: Mutex<Vec<Weak<Engine>>> = Mutex::new(Vec::new());
+}
+static EPOCH_TICKER_STARTED: Once = Once::new();
+
+fn register_for_epoch_ticking(engine: Arc<Engine>) {
+    let mut engines = EPOCH_TICKED_ENGINES.lock().unwrap();
+    let already_registered = engines
+        .iter()
+        .any(|weak| weak.upgrade().map_or(false, |e| Arc::ptr_eq(&e, &engine)));
+    if !already_registered {
+        engines.push(Arc::downgrade(&engine));
+    }
+    drop(engines);
+
+    EPOCH_TICKER_STARTED.call_once(|| {
+        thread::spawn(|| loop {
+            thread::sleep(Duration::from_secs(1));
+            EPOCH_TICKED_ENGINES.lock().unwrap().retain(|weak| {
+                weak.upgrade()
+                    .map(|engine| {
+                        engine.increment_epoch();
+                        true
+                    })
+                    .unwrap_or(false)
+            });
+        });
+    });
+}
+
+fn is_epoch_deadline_trap(error: &wapc::errors::Error) -> bool {
+    error_message_indicates_epoch_deadline(&error.to_string())
+}
+
+fn error_message_indicates_epoch_deadline(message: &str) -> bool {
+    message.contains("epoch deadline")
 }
 
 #[derive(Serialize)]
@@ -38,20 +96,42 @@ impl ValidateRequest {
     }
 }
 
-pub(crate) fn host_callback(
-    policy_id: u64,
-    binding: &str,
-    namespace: &str,
-    operation: &str,
-    payload: &[u8],
-) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
-    match binding {
+/// Builds the waPC host callback for one `PolicyEvaluator`. Each evaluator
+/// owns its own `callback_handler_tx`, so two policies sharing the same
+/// `wapc_policy_id` (waPC reuses ids across independent hosts) can no
+/// longer step on each other's state the way they could when every
+/// evaluator reached into a single process-wide map.
+///
+/// The callback itself stays synchronous, as required by `WapcHost::new`,
+/// so it bridges into the async `CallbackHandler` with a blocking send and
+/// a blocking wait on the per-request `oneshot` reply. Because of that,
+/// whatever calls into waPC and triggers this callback (`validate`,
+/// `validate_settings`, `protocol_version`) must not run inside an async
+/// runtime context (including the one the `CallbackHandler` is spawned
+/// on) — see the warning on those methods.
+fn build_host_callback(
+    current_policy: Arc<RwLock<Policy>>,
+    callback_handler_tx: CallbackSender,
+) -> impl Fn(u64, &str, &str, &str, &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>>
+{
+    move |_policy_id, binding, namespace, operation, payload| match binding {
         "kubewarden" => match namespace {
             "tracing" => match operation {
                 "log" => {
-                    let policy_mapping = POLICY_MAPPING.read().unwrap();
-                    let policy = policy_mapping.get(&policy_id).unwrap();
-                    if let Err(e) = policy.log(payload) {
+                    let policy = current_policy.read().unwrap().clone();
+                    let (response_tx, response_rx) = oneshot::channel();
+                    callback_handler_tx
+                        .blocking_send(CallbackRequest::LogEvent {
+                            policy,
+                            message: payload.to_vec(),
+                            response_channel: response_tx,
+                        })
+                        .map_err(|e| format!("cannot reach callback handler: {}", e))?;
+                    if let Err(e) = response_rx
+                        .blocking_recv()
+                        .map_err(|e| anyhow!("callback handler dropped the response: {}", e))
+                        .and_then(|r| r)
+                    {
                         let p =
                             String::from_utf8(payload.to_vec()).unwrap_or_else(|e| e.to_string());
                         error!(
@@ -73,16 +153,30 @@ pub(crate) fn host_callback(
             }
         },
         "kubernetes" => {
-            let cluster_context = ClusterContext::get();
-            match namespace {
-                "ingresses" => Ok(cluster_context.ingresses().into()),
-                "namespaces" => Ok(cluster_context.namespaces().into()),
-                "services" => Ok(cluster_context.services().into()),
+            let kind = match namespace {
+                "ingresses" => "Ingress",
+                "namespaces" => "Namespace",
+                "services" => "Service",
                 _ => {
                     error!("unknown namespace: {}", namespace);
-                    Err(format!("unknown namespace: {}", namespace).into())
+                    return Err(format!("unknown namespace: {}", namespace).into());
                 }
-            }
+            };
+            let (response_tx, response_rx) = oneshot::channel();
+            callback_handler_tx
+                .blocking_send(CallbackRequest::ListResources {
+                    api_version: "v1".to_string(),
+                    kind: kind.to_string(),
+                    namespace: None,
+                    label_selector: None,
+                    field_selector: None,
+                    response_channel: response_tx,
+                })
+                .map_err(|e| format!("cannot reach callback handler: {}", e))?;
+            response_rx
+                .blocking_recv()
+                .map_err(|e| format!("callback handler dropped the response: {}", e))?
+                .map_err(|e| e.to_string().into())
         }
         _ => {
             error!("unknown binding: {}", binding);
@@ -92,14 +186,24 @@ pub(crate) fn host_callback(
 }
 
 pub struct PolicyEvaluator {
+    // Keeps the `Arc<Engine>` registered with the epoch ticker alive for as
+    // long as the evaluator exists, so the ticker's `Weak` keeps upgrading.
+    _engine: Arc<Engine>,
     wapc_host: WapcHost,
     policy: Policy,
+    current_policy: Arc<RwLock<Policy>>,
+    engine_provider: WasmtimeEngineProvider,
+    policy_evaluation_limit_seconds: Option<u64>,
 }
 
 impl fmt::Debug for PolicyEvaluator {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("PolicyEvaluator")
             .field("settings", &self.policy.settings)
+            .field(
+                "policy_evaluation_limit_seconds",
+                &self.policy_evaluation_limit_seconds,
+            )
             .finish()
     }
 }
@@ -108,16 +212,110 @@ impl PolicyEvaluator {
     pub fn from_file(
         policy_file: &Path,
         settings: Option<serde_json::Map<String, serde_json::Value>>,
+        policy_evaluation_limit_seconds: Option<u64>,
+        callback_handler_tx: CallbackSender,
     ) -> Result<PolicyEvaluator> {
-        PolicyEvaluator::from_contents(fs::read(policy_file)?, settings)
+        PolicyEvaluator::from_contents(
+            fs::read(policy_file)?,
+            settings,
+            policy_evaluation_limit_seconds,
+            callback_handler_tx,
+        )
     }
 
     pub fn from_contents(
         policy_contents: Vec<u8>,
         settings: Option<serde_json::Map<String, serde_json::Value>>,
+        policy_evaluation_limit_seconds: Option<u64>,
+        callback_handler_tx: CallbackSender,
+    ) -> Result<PolicyEvaluator> {
+        let mut config = Config::new();
+        if policy_evaluation_limit_seconds.is_some() {
+            config.epoch_interruption(true);
+        }
+        let engine = Arc::new(Engine::new(&config)?);
+        let module = Module::new(&engine, &policy_contents)
+            .map_err(|e| anyhow!("cannot compile policy module: {}", e))?;
+
+        PolicyEvaluator::from_engine_and_module(
+            engine,
+            module,
+            policy_contents,
+            settings,
+            policy_evaluation_limit_seconds,
+            callback_handler_tx,
+        )
+    }
+
+    /// Authenticates `policy_contents` against `signatures` before building
+    /// an evaluator from it. Verification runs on the raw Wasm bytes, ahead
+    /// of `from_contents`'s compilation step, so a module whose signature
+    /// doesn't match a trusted identity in `config` is rejected without
+    /// ever being handed to Wasmtime.
+    pub fn from_verified_contents(
+        policy_contents: Vec<u8>,
+        signatures: &[PolicySignature],
+        config: &VerificationConfig,
+        settings: Option<serde_json::Map<String, serde_json::Value>>,
+        policy_evaluation_limit_seconds: Option<u64>,
+        callback_handler_tx: CallbackSender,
+    ) -> Result<PolicyEvaluator> {
+        verification_config::verify(&policy_contents, signatures, config)?;
+
+        PolicyEvaluator::from_contents(
+            policy_contents,
+            settings,
+            policy_evaluation_limit_seconds,
+            callback_handler_tx,
+        )
+    }
+
+    /// Builds an evaluator from a `PrecompiledPolicy`, skipping the parse
+    /// and JIT-compilation step `from_contents` pays every time. Use this
+    /// when the same policy is instantiated many times, e.g. once per
+    /// admission request, so the compile cost is paid at most once.
+    pub fn from_precompiled(
+        precompiled: &PrecompiledPolicy,
+        settings: Option<serde_json::Map<String, serde_json::Value>>,
+        policy_evaluation_limit_seconds: Option<u64>,
+        callback_handler_tx: CallbackSender,
+    ) -> Result<PolicyEvaluator> {
+        if policy_evaluation_limit_seconds.is_some() && !precompiled.epoch_interruption_enabled {
+            return Err(anyhow!(
+                "policy_evaluation_limit_seconds was requested, but this PrecompiledPolicy was \
+                 compiled against an engine without epoch_interruption enabled, so the limit \
+                 can never be enforced"
+            ));
+        }
+
+        PolicyEvaluator::from_engine_and_module(
+            precompiled.engine.clone(),
+            precompiled.module.clone(),
+            precompiled.policy_contents.as_ref().clone(),
+            settings,
+            policy_evaluation_limit_seconds,
+            callback_handler_tx,
+        )
+    }
+
+    fn from_engine_and_module(
+        engine: Arc<Engine>,
+        module: Module,
+        policy_contents: Vec<u8>,
+        settings: Option<serde_json::Map<String, serde_json::Value>>,
+        policy_evaluation_limit_seconds: Option<u64>,
+        callback_handler_tx: CallbackSender,
     ) -> Result<PolicyEvaluator> {
-        let engine = WasmtimeEngineProvider::new(&policy_contents, None);
-        let wapc_host = WapcHost::new(Box::new(engine), host_callback)?;
+        if policy_evaluation_limit_seconds.is_some() {
+            register_for_epoch_ticking(engine.clone());
+        }
+
+        let current_policy = Arc::new(RwLock::new(Policy::default()));
+        let host_callback = build_host_callback(current_policy.clone(), callback_handler_tx);
+
+        let engine_provider =
+            WasmtimeEngineProvider::new_with_module(module, None, (*engine).clone());
+        let wapc_host = WapcHost::new(Box::new(engine_provider.clone()), host_callback)?;
         let policy = PolicyEvaluator::from_contents_internal(
             policy_contents,
             || Ok(wapc_host.id()),
@@ -126,8 +324,26 @@ impl PolicyEvaluator {
             },
             settings,
         )?;
+        *current_policy.write().unwrap() = policy.clone();
+
+        Ok(PolicyEvaluator {
+            _engine: engine,
+            wapc_host,
+            policy,
+            current_policy,
+            engine_provider,
+            policy_evaluation_limit_seconds,
+        })
+    }
 
-        Ok(PolicyEvaluator { wapc_host, policy })
+    /// Arms the epoch deadline that bounds the next `wapc_host.call`, when a
+    /// `policy_evaluation_limit_seconds` was configured for this evaluator.
+    /// Ticks happen once a second (see `register_for_epoch_ticking`), so the
+    /// deadline is expressed directly in seconds.
+    fn arm_evaluation_deadline(&self) {
+        if let Some(limit) = self.policy_evaluation_limit_seconds {
+            self.engine_provider.set_epoch_deadline(limit);
+        }
     }
 
     fn from_contents_internal<E, P>(
@@ -153,15 +369,17 @@ impl PolicyEvaluator {
             policy_name = tracing::field::Empty,
         );
 
-        let policy = policy_from_contents(policy_contents, wapc_policy_id, span, settings)?;
-        POLICY_MAPPING
-            .write()
-            .unwrap()
-            .insert(wapc_policy_id, policy.clone());
-
-        Ok(policy)
+        policy_from_contents(policy_contents, wapc_policy_id, span, settings)
     }
 
+    /// Evaluates `request` against the policy.
+    ///
+    /// The host callback this call may trigger bridges into the
+    /// `CallbackHandler` with a blocking channel send and a blocking
+    /// receive, so this must not be called from a task running on an async
+    /// runtime (the one the `CallbackHandler` is driven on, or any other) —
+    /// doing so panics on the first callback. Call it from a dedicated thread, or via
+    /// something like `tokio::task::spawn_blocking`, instead.
     pub fn validate(&self, request: ValidateRequest) -> ValidationResponse {
         self.policy.span.in_scope(|| {
             let uid = request.uid();
@@ -169,11 +387,7 @@ impl PolicyEvaluator {
                 request_uid: Some(uid.to_string()),
                 ..self.policy.clone()
             };
-
-            POLICY_MAPPING
-                .write()
-                .unwrap()
-                .insert(self.policy.wapc_policy_id, policy.clone());
+            *self.current_policy.write().unwrap() = policy.clone();
 
             let req_obj = match request.0.get("object") {
                 Some(req_obj) => req_obj,
@@ -202,6 +416,7 @@ impl PolicyEvaluator {
                     );
                 }
             };
+            self.arm_evaluation_deadline();
             match self.wapc_host.call("validate", validate_str.as_bytes()) {
                 Ok(res) => {
                     let pol_val_resp: Result<PolicyValidationResponse> =
@@ -227,6 +442,20 @@ impl PolicyEvaluator {
                             )
                         })
                 }
+                Err(e) if is_epoch_deadline_trap(&e) => {
+                    let limit = self.policy_evaluation_limit_seconds.unwrap_or_default();
+                    error!(
+                        limit_seconds = limit,
+                        "policy evaluation exceeded the configured time limit"
+                    );
+                    ValidationResponse::reject_internal_server_error(
+                        uid.to_string(),
+                        format!(
+                            "internal error: policy evaluation exceeded the {}s time limit",
+                            limit
+                        ),
+                    )
+                }
                 Err(e) => {
                     error!(error = e.to_string().as_str(), "waPC communication error");
                     ValidationResponse::reject_internal_server_error(uid.to_string(), e.to_string())
@@ -235,7 +464,14 @@ impl PolicyEvaluator {
         })
     }
 
+    /// Validates the policy's configured settings by calling into the
+    /// guest's `validate_settings` export.
+    ///
+    /// Same constraint as `validate`: this must not be called from a task
+    /// running on an async runtime, or it can panic on the first host
+    /// callback.
     pub fn validate_settings(&self) -> SettingsValidationResponse {
+        self.arm_evaluation_deadline();
         let settings_str = match &self.policy.settings {
             Some(settings) => match serde_json::to_string(settings) {
                 Ok(s) => s,
@@ -264,6 +500,20 @@ impl PolicyEvaluator {
                     message: Some(format!("error: {:?}", e)),
                 })
             }
+            Err(err) if is_epoch_deadline_trap(&err) => {
+                let limit = self.policy_evaluation_limit_seconds.unwrap_or_default();
+                error!(
+                    limit_seconds = limit,
+                    "settings validation exceeded the configured time limit"
+                );
+                SettingsValidationResponse {
+                    valid: false,
+                    message: Some(format!(
+                        "internal error: settings validation exceeded the {}s time limit",
+                        limit
+                    )),
+                }
+            }
             Err(err) => SettingsValidationResponse {
                 valid: false,
                 message: Some(format!(
@@ -274,7 +524,13 @@ impl PolicyEvaluator {
         }
     }
 
+    /// Reports the protocol version the guest's SDK was built against.
+    ///
+    /// Same constraint as `validate`: this must not be called from a task
+    /// running on an async runtime, or it can panic on the first host
+    /// callback.
     pub fn protocol_version(&self) -> Result<ProtocolVersion> {
+        self.arm_evaluation_deadline();
         match self.wapc_host.call("protocol_version", &[0; 0]) {
             Ok(res) => ProtocolVersion::try_from(res.clone()).map_err(|e| {
                 anyhow!(
@@ -283,6 +539,17 @@ impl PolicyEvaluator {
                     e
                 )
             }),
+            Err(err) if is_epoch_deadline_trap(&err) => {
+                let limit = self.policy_evaluation_limit_seconds.unwrap_or_default();
+                error!(
+                    limit_seconds = limit,
+                    "protocol version check exceeded the configured time limit"
+                );
+                Err(anyhow!(
+                    "internal error: protocol version check exceeded the {}s time limit",
+                    limit
+                ))
+            }
             Err(err) => Err(anyhow!(
                 "Cannot invoke 'protocol_version' waPC function: {:?}",
                 err
@@ -294,26 +561,76 @@ impl PolicyEvaluator {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use tokio::sync::mpsc;
 
     #[test]
-    fn policy_is_registered_in_the_mapping() -> Result<()> {
-        let policy = Policy::default();
-        let policy_id = 1;
+    fn host_callback_forwards_log_events_to_the_callback_handler() {
+        let current_policy = Arc::new(RwLock::new(Policy::default()));
+        let (tx, mut rx) = mpsc::channel(1);
+        let callback = build_host_callback(current_policy, tx);
+        let payload = br#"{"level":"info","message":"hello"}"#.to_vec();
 
-        assert!(!POLICY_MAPPING.read().unwrap().contains_key(&policy_id));
+        let handle = thread::spawn(move || callback(1, "kubewarden", "tracing", "log", &payload));
 
-        PolicyEvaluator::from_contents_internal(
-            Vec::new(),
-            || Ok(policy_id),
-            |_, _, _, _| Ok(policy.clone()),
-            None,
-        )?;
+        match rx.blocking_recv().expect("expected a CallbackRequest") {
+            CallbackRequest::LogEvent {
+                message,
+                response_channel,
+                ..
+            } => {
+                assert_eq!(message, br#"{"level":"info","message":"hello"}"#.to_vec());
+                response_channel.send(Ok(())).unwrap();
+            }
+            _ => panic!("expected a LogEvent request"),
+        }
+
+        assert!(handle.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn recognizes_the_wasmtime_epoch_deadline_trap() {
+        assert!(error_message_indicates_epoch_deadline(
+            "epoch deadline reached while executing the guest"
+        ));
+        assert!(!error_message_indicates_epoch_deadline(
+            "guest trapped: out of bounds memory access"
+        ));
+    }
+
+    #[test]
+    fn registering_the_same_engine_twice_ticks_it_only_once() {
+        let engine = Arc::new(Engine::new(&Config::new()).unwrap());
 
-        let policy_mapping = POLICY_MAPPING.read().unwrap();
+        register_for_epoch_ticking(engine.clone());
+        register_for_epoch_ticking(engine.clone());
 
-        assert!(policy_mapping.contains_key(&policy_id));
-        assert_eq!(policy_mapping[&policy_id], policy);
+        let matching_registrations = EPOCH_TICKED_ENGINES
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|weak| weak.upgrade().map_or(false, |e| Arc::ptr_eq(&e, &engine)))
+            .count();
+        assert_eq!(matching_registrations, 1);
+    }
+
+    #[test]
+    fn dropped_engines_are_no_longer_registered() {
+        let engine = Arc::new(Engine::new(&Config::new()).unwrap());
+        let weak = Arc::downgrade(&engine);
 
-        Ok(())
+        register_for_epoch_ticking(engine.clone());
+        drop(engine);
+
+        EPOCH_TICKED_ENGINES
+            .lock()
+            .unwrap()
+            .retain(|w| w.upgrade().is_some());
+
+        assert!(weak.upgrade().is_none());
+        assert!(!EPOCH_TICKED_ENGINES
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|w| w.ptr_eq(&weak)));
     }
 }
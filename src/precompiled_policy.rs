@@ -0,0 +1,130 @@
+use anyhow::{anyhow, Result};
+use std::sync::Arc;
+use wasmtime::{Engine, Module};
+
+/// A Wasm policy module that has already been parsed and JIT-compiled by
+/// Wasmtime. Building a `PolicyEvaluator` from one of these, via
+/// `PolicyEvaluator::from_precompiled`, skips the compilation step that
+/// `PolicyEvaluator::from_contents` pays on every call, which matters once
+/// a long-running host instantiates the same policy hundreds of times.
+#[derive(Clone)]
+pub struct PrecompiledPolicy {
+    pub(crate) engine: Arc<Engine>,
+    pub(crate) module: Module,
+    pub(crate) policy_contents: Arc<Vec<u8>>,
+    /// Whether `engine`'s `Config` had `epoch_interruption(true)` set.
+    /// Epoch-interruption checks are compiled into the module at
+    /// compile/deserialize time, so this can't be changed later: a
+    /// `PrecompiledPolicy` built from an engine without it can never honor
+    /// a `policy_evaluation_limit_seconds` passed to `from_precompiled`.
+    pub(crate) epoch_interruption_enabled: bool,
+}
+
+impl PrecompiledPolicy {
+    /// Compiles `policy_contents` once against `engine`. The resulting
+    /// `PrecompiledPolicy` can be used directly, or serialized with
+    /// `to_bytes` and reloaded later with `from_serialized`.
+    ///
+    /// `epoch_interruption_enabled` must match whether `engine`'s `Config`
+    /// had `epoch_interruption(true)` set: pass `true` if the caller intends
+    /// to use `PolicyEvaluator::from_precompiled` with a
+    /// `policy_evaluation_limit_seconds`, so that call can enforce the
+    /// limit instead of silently ignoring it.
+    pub fn compile(
+        engine: Arc<Engine>,
+        policy_contents: Vec<u8>,
+        epoch_interruption_enabled: bool,
+    ) -> Result<PrecompiledPolicy> {
+        let module = Module::new(&engine, &policy_contents)
+            .map_err(|e| anyhow!("cannot compile policy module: {}", e))?;
+
+        Ok(PrecompiledPolicy {
+            engine,
+            module,
+            policy_contents: Arc::new(policy_contents),
+            epoch_interruption_enabled,
+        })
+    }
+
+    /// Reloads a module previously produced by `to_bytes`, skipping
+    /// compilation entirely. `Module::deserialize` checks that the
+    /// artifact was produced by a compatible `Engine` (same Wasmtime
+    /// version, target and `Config`) and errors out rather than risk
+    /// loading a stale or foreign cache entry.
+    ///
+    /// `epoch_interruption_enabled` must match whether `engine`'s `Config`
+    /// had `epoch_interruption(true)` set, same as in `compile`.
+    pub fn from_serialized(
+        engine: Arc<Engine>,
+        policy_contents: Vec<u8>,
+        serialized_module: &[u8],
+        epoch_interruption_enabled: bool,
+    ) -> Result<PrecompiledPolicy> {
+        // Safety: `Module::deserialize` requires the caller to only pass in
+        // bytes that were produced by `Module::serialize`/`Engine::precompile_module`
+        // of a compatible engine; the engine-compatibility check that
+        // `Module::deserialize` performs is what makes this safe in practice
+        // for artifacts we cached ourselves.
+        let module = unsafe {
+            Module::deserialize(&engine, serialized_module)
+                .map_err(|e| anyhow!("cannot load precompiled policy module: {}", e))?
+        };
+
+        Ok(PrecompiledPolicy {
+            engine,
+            module,
+            policy_contents: Arc::new(policy_contents),
+            epoch_interruption_enabled,
+        })
+    }
+
+    /// Serializes the compiled module so it can be cached to disk and
+    /// reloaded with `from_serialized`, instead of recompiling the policy
+    /// the next time the host starts up.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        self.module
+            .serialize()
+            .map_err(|e| anyhow!("cannot serialize precompiled policy module: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasmtime::Config;
+
+    // The minimal valid Wasm module: just the magic number and version.
+    const EMPTY_MODULE: &[u8] = b"\0asm\x01\0\0\0";
+
+    #[test]
+    fn serialized_module_round_trips_through_from_serialized() {
+        let mut config = Config::new();
+        config.epoch_interruption(true);
+        let engine = Arc::new(Engine::new(&config).unwrap());
+        let policy_contents = EMPTY_MODULE.to_vec();
+        let precompiled =
+            PrecompiledPolicy::compile(engine.clone(), policy_contents.clone(), true).unwrap();
+
+        let serialized = precompiled.to_bytes().unwrap();
+        let reloaded =
+            PrecompiledPolicy::from_serialized(engine, policy_contents.clone(), &serialized, true)
+                .unwrap();
+
+        assert_eq!(*reloaded.policy_contents, policy_contents);
+        assert!(reloaded.epoch_interruption_enabled);
+    }
+
+    #[test]
+    fn from_serialized_rejects_bytes_that_are_not_a_valid_module_cache() {
+        let engine = Arc::new(Engine::new(&Config::new()).unwrap());
+
+        let result = PrecompiledPolicy::from_serialized(
+            engine,
+            EMPTY_MODULE.to_vec(),
+            b"not a real serialized module",
+            false,
+        );
+
+        assert!(result.is_err());
+    }
+}
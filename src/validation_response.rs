@@ -0,0 +1,84 @@
+use anyhow::Result;
+use serde::Serialize;
+
+use kubewarden_policy_sdk::response::ValidationResponse as PolicyValidationResponse;
+
+use crate::json_patch;
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct ValidationResponseStatus {
+    pub message: Option<String>,
+    pub code: Option<u16>,
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct ValidationResponse {
+    pub uid: String,
+    pub allowed: bool,
+    pub status: Option<ValidationResponseStatus>,
+    #[serde(rename = "patchType", skip_serializing_if = "Option::is_none")]
+    pub patch_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub patch: Option<String>,
+}
+
+impl ValidationResponse {
+    pub fn reject(uid: String, message: String, code: u16) -> ValidationResponse {
+        ValidationResponse {
+            uid,
+            allowed: false,
+            status: Some(ValidationResponseStatus {
+                message: Some(message),
+                code: Some(code),
+            }),
+            patch_type: None,
+            patch: None,
+        }
+    }
+
+    pub fn reject_internal_server_error(uid: String, message: String) -> ValidationResponse {
+        ValidationResponse::reject(
+            uid,
+            message,
+            hyper::StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+        )
+    }
+
+    /// Builds the response a Kubernetes API server expects from the guest's
+    /// verdict. When the guest is a mutating policy and returns a
+    /// `mutated_object`, the diff against `req_obj` is computed as an RFC
+    /// 6902 JSON Patch and attached via `patch`/`patchType`; validating-only
+    /// guests, which never set `mutated_object`, keep producing a plain
+    /// accept/reject response exactly as before.
+    pub fn from_policy_validation_response(
+        uid: String,
+        req_obj: &serde_json::Value,
+        pol_val_resp: &PolicyValidationResponse,
+    ) -> Result<ValidationResponse> {
+        let status = pol_val_resp
+            .message
+            .clone()
+            .map(|message| ValidationResponseStatus {
+                message: Some(message),
+                code: pol_val_resp.code,
+            });
+
+        let mut response = ValidationResponse {
+            uid,
+            allowed: pol_val_resp.accepted,
+            status,
+            patch_type: None,
+            patch: None,
+        };
+
+        if let Some(mutated_object) = &pol_val_resp.mutated_object {
+            let operations = json_patch::diff(req_obj, mutated_object);
+            if !operations.is_empty() {
+                response.patch = Some(json_patch::to_base64(&operations)?);
+                response.patch_type = Some("JSONPatch".to_string());
+            }
+        }
+
+        Ok(response)
+    }
+}
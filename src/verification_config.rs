@@ -0,0 +1,291 @@
+use anyhow::{anyhow, Result};
+use ring::digest;
+use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1};
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::{GeneralName, ParsedExtension};
+use x509_parser::oid_registry::Oid;
+use x509_parser::time::ASN1Time;
+
+/// The Fulcio extension that carries the OIDC issuer a keyless certificate
+/// was minted against. See
+/// https://github.com/sigstore/fulcio/blob/main/docs/oid-info.md#1361415726411-issuer.
+const FULCIO_OIDC_ISSUER_OID: &[u64] = &[1, 3, 6, 1, 4, 1, 57264, 1, 1];
+
+/// Where a policy's signing identity comes from: a long-lived public key
+/// the operator trusts directly, or a keyless (Fulcio) identity backed by
+/// an OIDC issuer/subject instead of a key the operator manages.
+#[derive(Clone, Debug)]
+pub enum TrustedIdentity {
+    PublicKey {
+        /// ECDSA P-256 public key as an uncompressed SEC1 point (the bit
+        /// string inside a SubjectPublicKeyInfo, not the whole DER structure).
+        der_bytes: Vec<u8>,
+    },
+    Keyless {
+        /// OIDC issuer the signing certificate must have been minted for,
+        /// e.g. `https://github.com/login/oauth`.
+        issuer: String,
+        /// Glob-style pattern matched against the certificate's SAN, e.g.
+        /// `https://github.com/kubewarden/*`.
+        subject_pattern: String,
+    },
+}
+
+/// Describes which signing identities `PolicyEvaluator::from_verified_contents`
+/// accepts. A module must carry at least one signature that matches one of
+/// the configured identities, or verification fails and the evaluator is
+/// never built.
+#[derive(Clone, Debug, Default)]
+pub struct VerificationConfig {
+    pub trusted_identities: Vec<TrustedIdentity>,
+    /// DER-encoded CA certificates (e.g. the Fulcio root and intermediate)
+    /// that a keyless signature's certificate must chain to. A keyless
+    /// signature whose certificate isn't signed by one of these is rejected
+    /// regardless of what its issuer/SAN fields claim.
+    pub trusted_root_certs: Vec<Vec<u8>>,
+}
+
+/// A single detached signature over a policy module's digest. `certificate`
+/// is only present for keyless signatures, and carries the DER-encoded,
+/// short-lived Fulcio certificate the signature was produced under. Its
+/// fields are untrusted until `verify_one` has checked it chains to one of
+/// `VerificationConfig::trusted_root_certs`.
+#[derive(Clone, Debug)]
+pub struct PolicySignature {
+    pub signature: Vec<u8>,
+    pub certificate: Option<Vec<u8>>,
+}
+
+/// The handful of fields out of a keyless certificate that matter for
+/// verification, populated only after `verify_certificate_chain` has
+/// confirmed the certificate chains to a trusted root. Never build one of
+/// these directly from caller-supplied data.
+struct VerifiedIdentity {
+    subject_alternative_name: String,
+    issuer: String,
+    der_public_key: Vec<u8>,
+}
+
+/// Checks that `policy_contents` carries at least one signature matching a
+/// configured trusted identity. Runs on the raw Wasm bytes before they are
+/// ever handed to Wasmtime, so an unverified module is never compiled, let
+/// alone instantiated.
+pub fn verify(
+    policy_contents: &[u8],
+    signatures: &[PolicySignature],
+    config: &VerificationConfig,
+) -> Result<()> {
+    if config.trusted_identities.is_empty() {
+        return Err(anyhow!(
+            "verification config does not list any trusted identity"
+        ));
+    }
+    if signatures.is_empty() {
+        return Err(anyhow!("policy module has no signatures to verify"));
+    }
+
+    let module_digest = digest::digest(&digest::SHA256, policy_contents);
+
+    for signature in signatures {
+        if verify_one(&module_digest, signature, config)? {
+            return Ok(());
+        }
+    }
+
+    Err(anyhow!(
+        "none of the policy's signatures match a trusted identity"
+    ))
+}
+
+fn verify_one(
+    module_digest: &digest::Digest,
+    signature: &PolicySignature,
+    config: &VerificationConfig,
+) -> Result<bool> {
+    match &signature.certificate {
+        Some(cert_der) => {
+            let identity = match verify_certificate_chain(cert_der, &config.trusted_root_certs) {
+                Ok(identity) => identity,
+                Err(_) => return Ok(false),
+            };
+            let identity_is_trusted = config.trusted_identities.iter().any(|identity_config| {
+                matches!(
+                    identity_config,
+                    TrustedIdentity::Keyless { issuer, subject_pattern }
+                        if &identity.issuer == issuer
+                            && subject_matches(&identity.subject_alternative_name, subject_pattern)
+                )
+            });
+            if !identity_is_trusted {
+                return Ok(false);
+            }
+            Ok(verify_signature(
+                &identity.der_public_key,
+                module_digest,
+                &signature.signature,
+            ))
+        }
+        None => {
+            for identity in &config.trusted_identities {
+                if let TrustedIdentity::PublicKey { der_bytes } = identity {
+                    if verify_signature(der_bytes, module_digest, &signature.signature) {
+                        return Ok(true);
+                    }
+                }
+            }
+            Ok(false)
+        }
+    }
+}
+
+/// Parses `cert_der` and confirms it is signed by one of `trusted_roots` and
+/// currently within its validity period, before extracting the identity
+/// fields sigstore keyless verification relies on. A certificate that
+/// doesn't chain to a configured root is rejected here, so the issuer/SAN
+/// strings it carries are never trusted on their own — they only reach the
+/// caller once the certificate itself has been authenticated.
+fn verify_certificate_chain(cert_der: &[u8], trusted_roots: &[Vec<u8>]) -> Result<VerifiedIdentity> {
+    if trusted_roots.is_empty() {
+        return Err(anyhow!(
+            "verification config does not list any trusted root certificate"
+        ));
+    }
+
+    let (_, cert) = x509_parser::parse_x509_certificate(cert_der)
+        .map_err(|e| anyhow!("cannot parse signing certificate: {}", e))?;
+
+    if !cert.validity().is_valid_at(ASN1Time::now()) {
+        return Err(anyhow!("signing certificate is expired or not yet valid"));
+    }
+
+    let signed_by_trusted_root = trusted_roots.iter().any(|root_der| {
+        x509_parser::parse_x509_certificate(root_der)
+            .ok()
+            .map(|(_, root)| cert.verify_signature(Some(root.public_key())).is_ok())
+            .unwrap_or(false)
+    });
+    if !signed_by_trusted_root {
+        return Err(anyhow!(
+            "signing certificate does not chain to a trusted root"
+        ));
+    }
+
+    Ok(VerifiedIdentity {
+        subject_alternative_name: certificate_subject_alternative_name(&cert)?,
+        issuer: certificate_fulcio_issuer(&cert)?,
+        der_public_key: cert.public_key().subject_public_key.data.to_vec(),
+    })
+}
+
+fn certificate_subject_alternative_name(cert: &X509Certificate) -> Result<String> {
+    let extension = cert
+        .subject_alternative_name()
+        .map_err(|e| anyhow!("cannot parse subject alternative name: {}", e))?
+        .ok_or_else(|| anyhow!("signing certificate has no subject alternative name"))?;
+
+    match extension.parsed_extension() {
+        ParsedExtension::SubjectAlternativeName(san) => san
+            .general_names
+            .iter()
+            .find_map(|name| match name {
+                GeneralName::URI(uri) => Some(uri.to_string()),
+                _ => None,
+            })
+            .ok_or_else(|| anyhow!("subject alternative name has no URI entry")),
+        _ => Err(anyhow!("unexpected subject alternative name extension")),
+    }
+}
+
+fn certificate_fulcio_issuer(cert: &X509Certificate) -> Result<String> {
+    let oid = Oid::from(FULCIO_OIDC_ISSUER_OID)
+        .map_err(|_| anyhow!("invalid Fulcio issuer OID"))?;
+    let extension = cert
+        .get_extension_unique(&oid)
+        .map_err(|e| anyhow!("cannot parse Fulcio issuer extension: {}", e))?
+        .ok_or_else(|| anyhow!("signing certificate has no Fulcio issuer extension"))?;
+
+    std::str::from_utf8(extension.value)
+        .map(|s| s.trim_matches(char::from(0)).to_string())
+        .map_err(|_| anyhow!("Fulcio issuer extension is not valid UTF-8"))
+}
+
+fn verify_signature(
+    der_public_key: &[u8],
+    module_digest: &digest::Digest,
+    signature: &[u8],
+) -> bool {
+    UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, der_public_key)
+        .verify(module_digest.as_ref(), signature)
+        .is_ok()
+}
+
+/// A tiny glob matcher: `pattern` may end in `*` to mean "starts with",
+/// which is all sigstore subject patterns need in practice (matching every
+/// identity under an org or repo).
+fn subject_matches(subject: &str, pattern: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => subject.starts_with(prefix),
+        None => subject == pattern,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{Certificate, CertificateParams, DistinguishedName};
+
+    fn self_signed_cert(subject_alt_names: Vec<String>) -> (Vec<u8>, Vec<u8>) {
+        let mut params = CertificateParams::new(subject_alt_names);
+        params.distinguished_name = DistinguishedName::new();
+        let cert = Certificate::from_params(params).unwrap();
+        let der = cert.serialize_der().unwrap();
+        let key_der = cert.serialize_private_key_der();
+        (der, key_der)
+    }
+
+    #[test]
+    fn rejects_a_certificate_not_signed_by_a_trusted_root() {
+        // A certificate an attacker minted themselves: it's a perfectly
+        // valid, self-signed certificate, but it wasn't issued by any root
+        // the verifier is configured to trust.
+        let (forged_cert_der, _) = self_signed_cert(vec!["https://github.com/kubewarden/policy".to_string()]);
+        let (unrelated_root_der, _) = self_signed_cert(vec!["unrelated-root".to_string()]);
+
+        let result = verify_certificate_chain(&forged_cert_der, &[unrelated_root_der]);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn rejects_keyless_signature_when_no_trusted_root_is_configured() {
+        let (cert_der, _) = self_signed_cert(vec!["https://github.com/kubewarden/policy".to_string()]);
+        let signature = PolicySignature {
+            signature: vec![0u8; 64],
+            certificate: Some(cert_der),
+        };
+        let config = VerificationConfig {
+            trusted_identities: vec![TrustedIdentity::Keyless {
+                issuer: "https://github.com/login/oauth".to_string(),
+                subject_pattern: "https://github.com/kubewarden/*".to_string(),
+            }],
+            trusted_root_certs: vec![],
+        };
+        let module_digest = digest::digest(&digest::SHA256, b"wasm module contents");
+
+        let matched = verify_one(&module_digest, &signature, &config).unwrap();
+
+        assert!(!matched);
+    }
+
+    #[test]
+    fn subject_pattern_with_wildcard_matches_by_prefix() {
+        assert!(subject_matches(
+            "https://github.com/kubewarden/policy",
+            "https://github.com/kubewarden/*"
+        ));
+        assert!(!subject_matches(
+            "https://github.com/someone-else/policy",
+            "https://github.com/kubewarden/*"
+        ));
+    }
+}